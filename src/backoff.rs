@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+/// Exponential backoff capped at 10s, starting at 500ms. Used by the various
+/// "wait for AWS state to change" polling loops so they yield to the tokio
+/// executor between polls instead of busy-blocking the worker thread.
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            next: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Backoff {
+    pub async fn wait(&mut self) {
+        tokio::time::sleep(self.next).await;
+        self.next = (self.next * 2).min(Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,474 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rand::distr::Alphanumeric;
+use rand::prelude::*;
+use rusoto_core::Region;
+use rusoto_ec2::{
+    AuthorizeSecurityGroupIngressRequest, CancelSpotInstanceRequestsRequest, CreateKeyPairRequest,
+    CreateSecurityGroupRequest, DescribeInstancesRequest, DescribeSpotInstanceRequestsRequest,
+    Ec2, Ec2Client, IpPermission, IpRange, RequestSpotInstancesRequest,
+    RequestSpotLaunchSpecification, RunInstancesRequest,
+};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+use tempfile;
+
+use crate::backoff::Backoff;
+use crate::resource_guard::ResourceGuard;
+use crate::Machine;
+
+/// Spot request status codes that mean "this will not be fulfilled any time
+/// soon", as opposed to a transient `pending-evaluation`/`pending-fulfillment`.
+const NO_CAPACITY_STATUS_CODES: &[&str] = &["capacity-not-available", "price-too-low"];
+
+/// What a `Provider` needs to know in order to bring up a named set of
+/// machines. This mirrors the fields of `MachineSetup` that actually matter
+/// for provisioning; the setup closure stays with `FlotillaBuilder` since
+/// it's only relevant once a machine is reachable over SSH.
+pub struct MachineDescriptor {
+    pub instance_type: String,
+    pub ami: String,
+    pub count: u32,
+    pub prefer_spot: bool,
+}
+
+/// A backend capable of provisioning and tearing down a named collection of
+/// machines. `AwsProvider` wraps the rusoto/EC2 spot-instance logic that
+/// used to live directly in `FlotillaBuilder::run`; other backends (e.g.
+/// bare-metal/localhost) can implement this trait and plug into the same
+/// `MachineSetup`/`Machine` pipeline.
+#[async_trait]
+pub trait Provider: Send + Sync {
+    /// Bring up the requested machines and return them keyed by set name.
+    async fn provision(
+        &mut self,
+        descriptors: HashMap<String, MachineDescriptor>,
+    ) -> Result<HashMap<String, Vec<Machine>>>;
+
+    /// Tear down anything `provision` created.
+    async fn teardown(&self) -> Result<()>;
+
+    /// Path to the private key that should be used to SSH into the
+    /// machines returned by `provision`.
+    fn key_path(&self) -> &Path;
+}
+
+/// Launch `count` on-demand instances equivalent to `launch`, returning the
+/// resulting instance ids.
+async fn run_on_demand(
+    ec2: &Ec2Client,
+    launch: &RequestSpotLaunchSpecification,
+    count: u32,
+) -> Result<Vec<String>> {
+    let mut req = RunInstancesRequest::default();
+    req.image_id = launch.image_id.clone();
+    req.instance_type = launch.instance_type.clone();
+    req.security_group_ids = launch.security_group_ids.clone();
+    req.key_name = launch.key_name.clone();
+    req.min_count = i64::from(count);
+    req.max_count = i64::from(count);
+
+    let res = ec2
+        .run_instances(req)
+        .await
+        .context("Failed to run on-demand instances")?;
+
+    Ok(res
+        .instances
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|i| i.instance_id)
+        .collect())
+}
+
+/// The default `Provider`: EC2 spot instances in a single region, fronted by
+/// a security group and key pair created for the duration of the run.
+pub struct AwsProvider {
+    region: Region,
+    fallback_to_on_demand: bool,
+    spot_capacity_timeout: Duration,
+    provision_timeout: Duration,
+    key_file: Option<tempfile::NamedTempFile>,
+    guard: Option<ResourceGuard>,
+}
+
+impl AwsProvider {
+    pub fn new(region: Region) -> Self {
+        AwsProvider {
+            region,
+            fallback_to_on_demand: true,
+            spot_capacity_timeout: Duration::from_secs(120),
+            provision_timeout: Duration::from_secs(20 * 60),
+            key_file: None,
+            guard: None,
+        }
+    }
+
+    /// Whether a `MachineDescriptor` with `prefer_spot` set should fall back
+    /// to an on-demand instance once its spot request has sat `open` with a
+    /// no-capacity/price-too-low status past `spot_capacity_timeout`.
+    /// Enabled by default.
+    pub fn fallback_to_on_demand(&mut self, enabled: bool) {
+        self.fallback_to_on_demand = enabled;
+    }
+
+    /// How long to let a spot request sit unfulfilled before falling back to
+    /// on-demand (only consulted when `fallback_to_on_demand` is enabled).
+    pub fn spot_capacity_timeout(&mut self, timeout: Duration) {
+        self.spot_capacity_timeout = timeout;
+    }
+
+    /// Overall deadline for provisioning to reach a terminal state (spot
+    /// requests resolved, instances running) before `provision` gives up
+    /// and returns an error instead of polling forever. Defaults to 20
+    /// minutes.
+    pub fn provision_timeout(&mut self, timeout: Duration) {
+        self.provision_timeout = timeout;
+    }
+}
+
+#[async_trait]
+impl Provider for AwsProvider {
+    async fn provision(
+        &mut self,
+        descriptors: HashMap<String, MachineDescriptor>,
+    ) -> Result<HashMap<String, Vec<Machine>>> {
+        let ec2 = Ec2Client::new(self.region.clone());
+        self.guard = Some(ResourceGuard::new(ec2.clone()));
+
+        // Setup Firewall for machines
+        let mut group_name = String::from("flotilla_security_");
+        group_name.extend(
+            rand::rng()
+                .sample_iter(Alphanumeric)
+                .take(10)
+                .map(char::from),
+        );
+        let mut req = CreateSecurityGroupRequest::default();
+        req.group_name = group_name;
+        req.description = "Security group for Flotilla Spot Instances".to_string();
+        let res = ec2
+            .create_security_group(req)
+            .await
+            .context("Failed to create security group for machines")?;
+
+        let group_id = res
+            .group_id
+            .expect("No Group ID found with the newly created security group");
+        self.guard.as_mut().unwrap().set_group_id(group_id.clone());
+
+        let mut update_sec_group_req = AuthorizeSecurityGroupIngressRequest::default();
+        update_sec_group_req.group_id = Some(group_id.clone());
+
+        let mut access = IpPermission::default();
+        access.ip_protocol = Some("tcp".to_string());
+        access.from_port = Some(22);
+        access.to_port = Some(22);
+        access.ip_ranges = Some(vec![IpRange {
+            cidr_ip: Some("0.0.0.0/0".to_string()),
+            ..Default::default()
+        }]);
+
+        let mut crosstalk = IpPermission::default();
+        crosstalk.ip_protocol = Some("tcp".to_string());
+        crosstalk.from_port = Some(0);
+        crosstalk.to_port = Some(65535);
+        crosstalk.ip_ranges = Some(vec![IpRange {
+            cidr_ip: Some("172.31.0.0/16".to_string()),
+            ..Default::default()
+        }]);
+
+        update_sec_group_req.ip_permissions = Some(vec![access, crosstalk]);
+
+        ec2.authorize_security_group_ingress(update_sec_group_req)
+            .await
+            .context("Updating Security Group Failed")?;
+
+        // Consturct Key-Pair for Ssh Acccess
+        let mut create_key_pair_req = CreateKeyPairRequest::default();
+        let mut key_name = "flotilla_key_".to_string();
+        key_name.extend(rand::rng().sample_iter(Alphanumeric).take(10).map(char::from));
+        create_key_pair_req.key_name = key_name.clone();
+        let key_pair_res = ec2
+            .create_key_pair(create_key_pair_req)
+            .await
+            .context("Failed to generate new key pair")?;
+
+        self.guard.as_mut().unwrap().set_key_name(key_name.clone());
+
+        let private_key = key_pair_res
+            .key_material
+            .expect("No Key material found for this key");
+
+        let mut private_key_file = tempfile::NamedTempFile::new()
+            .context("Failed to create temporary file for keypair")?;
+
+        private_key_file
+            .write_all(private_key.as_bytes())
+            .context("could not write private key to file")?;
+
+        let mut spot_request_ids = vec![];
+        let mut id_to_name = HashMap::new();
+        // Remembered so a spot request that never gets fulfilled can be
+        // replaced with an equivalent on-demand `run_instances` call.
+        let mut launch_specs: HashMap<String, RequestSpotLaunchSpecification> = HashMap::new();
+        let mut on_demand_instance_ids = vec![];
+
+        for (name, descriptor) in descriptors {
+            let mut launch = RequestSpotLaunchSpecification::default();
+            launch.image_id = Some(descriptor.ami);
+            launch.instance_type = Some(descriptor.instance_type);
+            launch.security_group_ids = Some(vec![group_id.clone()]);
+            launch.key_name = Some(key_name.to_string());
+
+            if !descriptor.prefer_spot {
+                let ids = run_on_demand(&ec2, &launch, descriptor.count)
+                    .await
+                    .context(format!("Failed to launch on-demand instances for {}", name))?;
+                for id in ids {
+                    id_to_name.insert(id.clone(), name.clone());
+                    self.guard.as_mut().unwrap().push_instance_id(id.clone());
+                    on_demand_instance_ids.push(id);
+                }
+                continue;
+            }
+
+            launch_specs.insert(name.clone(), launch.clone());
+
+            let mut req = RequestSpotInstancesRequest::default();
+            req.instance_count = Some(i64::from(descriptor.count));
+            req.launch_specification = Some(launch);
+
+            let res = ec2
+                .request_spot_instances(req)
+                .await
+                .context(format!("Failed to request spot instances for {}", name))?;
+
+            let res = res
+                .spot_instance_requests
+                .context("spot_instance_requests should always return spot instance requests.")?;
+
+            spot_request_ids.extend(
+                res.into_iter()
+                    .filter_map(|sir| sir.spot_instance_request_id)
+                    .map(|sir| {
+                        id_to_name.insert(sir.clone(), name.clone());
+                        sir
+                    }),
+            )
+        }
+
+        // Wait for spot instances to come up, falling back to on-demand for
+        // any request that sits `open` with a no-capacity status past the
+        // configured deadline.
+        let spot_wait_start = Instant::now();
+        let mut req = DescribeSpotInstanceRequestsRequest::default();
+        req.spot_instance_request_ids = Some(spot_request_ids.clone());
+        let mut instances: Vec<_> = vec![];
+        let mut all_active;
+        let mut backoff = Backoff::default();
+        loop {
+            if spot_wait_start.elapsed() > self.provision_timeout {
+                anyhow::bail!("timed out waiting for spot instance requests to resolve");
+            }
+
+            let res = ec2
+                .describe_spot_instance_requests(req.clone())
+                .await
+                .context("Failed to describe spot instances")?;
+            let spot_instance_requests = res.spot_instance_requests.unwrap_or_default();
+
+            if self.fallback_to_on_demand && spot_wait_start.elapsed() > self.spot_capacity_timeout
+            {
+                let stuck: Vec<_> = spot_instance_requests
+                    .iter()
+                    .filter(|sir| {
+                        sir.state.as_deref() == Some("open")
+                            && sir
+                                .status
+                                .as_ref()
+                                .and_then(|s| s.code.as_deref())
+                                .map_or(false, |code| NO_CAPACITY_STATUS_CODES.contains(&code))
+                    })
+                    .cloned()
+                    .collect();
+
+                if !stuck.is_empty() {
+                    let stuck_ids: Vec<_> = stuck
+                        .iter()
+                        .filter_map(|sir| sir.spot_instance_request_id.clone())
+                        .collect();
+
+                    let mut cancel = CancelSpotInstanceRequestsRequest::default();
+                    cancel.spot_instance_request_ids = stuck_ids.clone();
+                    ec2.cancel_spot_instance_requests(cancel)
+                        .await
+                        .context("failed to cancel unfulfilled spot instance requests")?;
+
+                    spot_request_ids.retain(|id| !stuck_ids.contains(id));
+                    req.spot_instance_request_ids = Some(spot_request_ids.clone());
+
+                    for sir_id in &stuck_ids {
+                        let name = id_to_name
+                            .remove(sir_id)
+                            .expect("every spot request id is made of some machine set");
+                        let launch = &launch_specs[&name];
+                        let ids = run_on_demand(&ec2, launch, 1)
+                            .await
+                            .context(format!("Failed to fall back to on-demand for {}", name))?;
+                        for id in ids {
+                            id_to_name.insert(id.clone(), name.clone());
+                            self.guard.as_mut().unwrap().push_instance_id(id.clone());
+                            instances.push(id);
+                        }
+                    }
+
+                    if spot_request_ids.is_empty() {
+                        all_active = true;
+                        break;
+                    }
+                }
+            }
+
+            let any_open = spot_instance_requests
+                .iter()
+                .any(|sir| sir.state.as_ref().map_or(false, |s| s == "open"));
+
+            if !any_open {
+                all_active = true;
+                for sir in spot_instance_requests {
+                    if sir.state.as_deref() != Some("active") {
+                        all_active = false;
+                        continue;
+                    }
+                    let instance_id = match sir.instance_id {
+                        Some(id) => id,
+                        None => {
+                            all_active = false;
+                            continue;
+                        }
+                    };
+                    let name = id_to_name
+                        .remove(
+                            &sir.spot_instance_request_id
+                                .expect("spot instance must have spot instance request id"),
+                        )
+                        .expect("every spot request id is made of some machine set");
+                    id_to_name.insert(instance_id.clone(), name);
+                    self.guard
+                        .as_mut()
+                        .unwrap()
+                        .push_instance_id(instance_id.clone());
+                    instances.push(instance_id);
+                }
+
+                break;
+            } else {
+                backoff.wait().await;
+            }
+        }
+
+        // Stop any spot requests that are still outstanding.
+        if !spot_request_ids.is_empty() {
+            let mut cancel = CancelSpotInstanceRequestsRequest::default();
+            cancel.spot_instance_request_ids = spot_request_ids;
+
+            ec2.cancel_spot_instance_requests(cancel)
+                .await
+                .context("failed to cancel spot instances")?;
+        }
+
+        instances.extend(on_demand_instance_ids);
+
+        // Wait until all instances are up
+        let mut machines: HashMap<String, Vec<Machine>> = HashMap::new();
+        let mut desc_req = DescribeInstancesRequest::default();
+        desc_req.instance_ids = Some(instances);
+        let mut all_machine_are_ready = false;
+        let mut backoff = Backoff::default();
+
+        while !all_machine_are_ready {
+            // Measured from the start of the spot wait, so `provision_timeout`
+            // bounds both waits together.
+            if spot_wait_start.elapsed() > self.provision_timeout {
+                anyhow::bail!("timed out waiting for instances to reach the running state");
+            }
+
+            all_machine_are_ready = true;
+            machines.clear();
+
+            let reservations = ec2
+                .describe_instances(desc_req.clone())
+                .await
+                .context("Failed to describe spot instances")?
+                .reservations
+                .unwrap_or_else(Vec::new);
+
+            for reservation in reservations {
+                for instance in reservation.instances.unwrap_or_else(Vec::new) {
+                    let state = instance
+                        .state
+                        .as_ref()
+                        .map(|s| s.name.as_deref().unwrap_or(""))
+                        .unwrap();
+
+                    if state != "running" {
+                        all_machine_are_ready = false;
+                        continue;
+                    }
+
+                    if instance.public_ip_address.is_none() {
+                        all_machine_are_ready = false;
+                        continue;
+                    }
+
+                    let machine = Machine {
+                        ssh: None,
+                        instance_type: instance.instance_type.unwrap(),
+                        private_ip: instance.private_ip_address.unwrap(),
+                        public_ip: instance.public_ip_address.unwrap(),
+                        dns: instance.public_dns_name.unwrap_or_default(),
+                    };
+                    let name = id_to_name[&instance.instance_id.unwrap()].clone();
+                    machines.entry(name).or_insert_with(Vec::new).push(machine);
+                }
+            }
+
+            if !all_machine_are_ready {
+                backoff.wait().await;
+            }
+        }
+
+        // TODO: Assert here that instances in each set is the same as requested.
+
+        if !all_active {
+            anyhow::bail!(
+                "one or more spot instance requests failed or were cancelled instead of \
+                 reaching the active state; aborting provision rather than run short-handed"
+            );
+        }
+
+        self.key_file = Some(private_key_file);
+
+        Ok(machines)
+    }
+
+    async fn teardown(&self) -> Result<()> {
+        self.guard
+            .as_ref()
+            .context("teardown called before provision")?
+            .teardown()
+            .await
+    }
+
+    fn key_path(&self) -> &Path {
+        self.key_file
+            .as_ref()
+            .expect("key_path called before provision")
+            .path()
+    }
+}
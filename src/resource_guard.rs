@@ -0,0 +1,182 @@
+use anyhow::{Context, Result};
+use rusoto_ec2::{
+    DeleteKeyPairRequest, DeleteSecurityGroupRequest, DescribeInstancesRequest, Ec2, Ec2Client,
+    TerminateInstancesRequest,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backoff::Backoff;
+
+/// How long to wait for terminated instances to actually reach the
+/// `terminated` state before giving up on deleting the security group that
+/// references them.
+const TERMINATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Tracks the AWS resources a `Provider::provision` call has created so far
+/// and guarantees they're cleaned up even if a setup closure panics or the
+/// experiment closure returns early with an error - cases where the happy
+/// path in `FlotillaBuilder::run` never reaches `Provider::teardown`.
+///
+/// Resources are recorded as they're created, so a guard that's dropped
+/// mid-provisioning only cleans up what actually exists. `Provider::teardown`
+/// performs the same cleanup on the happy path and disarms the guard
+/// afterwards so it doesn't run a second time.
+pub struct ResourceGuard {
+    ec2: Ec2Client,
+    group_id: Option<String>,
+    key_name: Option<String>,
+    instance_ids: Vec<String>,
+    armed: AtomicBool,
+}
+
+impl ResourceGuard {
+    pub fn new(ec2: Ec2Client) -> Self {
+        ResourceGuard {
+            ec2,
+            group_id: None,
+            key_name: None,
+            instance_ids: Vec::new(),
+            armed: AtomicBool::new(true),
+        }
+    }
+
+    pub fn set_group_id(&mut self, group_id: String) {
+        self.group_id = Some(group_id);
+    }
+
+    pub fn set_key_name(&mut self, key_name: String) {
+        self.key_name = Some(key_name);
+    }
+
+    pub fn push_instance_id(&mut self, instance_id: String) {
+        self.instance_ids.push(instance_id);
+    }
+
+    /// Run the normal, awaited cleanup and prevent `Drop` from repeating it.
+    pub async fn teardown(&self) -> Result<()> {
+        cleanup(
+            &self.ec2,
+            &self.instance_ids,
+            self.group_id.as_deref(),
+            self.key_name.as_deref(),
+        )
+        .await?;
+        self.armed.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for ResourceGuard {
+    fn drop(&mut self) {
+        if !self.armed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let ec2 = self.ec2.clone();
+        let instance_ids = std::mem::take(&mut self.instance_ids);
+        let group_id = self.group_id.take();
+        let key_name = self.key_name.take();
+
+        // Drop can't be async, so the cleanup future is driven to completion
+        // on a dedicated thread with its own single-threaded runtime.
+        let cleanup = thread::spawn(move || -> Result<()> {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("failed to start cleanup runtime")?;
+            rt.block_on(cleanup(
+                &ec2,
+                &instance_ids,
+                group_id.as_deref(),
+                key_name.as_deref(),
+            ))
+        });
+
+        match cleanup.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("flotilla: failed to clean up leaked AWS resources: {:#}", e),
+            Err(_) => eprintln!("flotilla: cleanup of leaked AWS resources panicked"),
+        }
+    }
+}
+
+async fn cleanup(
+    ec2: &Ec2Client,
+    instance_ids: &[String],
+    group_id: Option<&str>,
+    key_name: Option<&str>,
+) -> Result<()> {
+    if !instance_ids.is_empty() {
+        println!("Terminating Instances");
+        let mut req = TerminateInstancesRequest::default();
+        req.instance_ids = instance_ids.to_vec();
+        ec2.terminate_instances(req)
+            .await
+            .context("Failed to terminate flotilla instances")?;
+
+        // EC2 refuses to delete a security group still referenced by an
+        // instance that hasn't finished terminating.
+        wait_for_termination(ec2, instance_ids).await?;
+    }
+
+    if let Some(group_id) = group_id {
+        println!("Deleting security group");
+        let mut req = DeleteSecurityGroupRequest::default();
+        req.group_id = Some(group_id.to_string());
+        ec2.delete_security_group(req)
+            .await
+            .context("Failed to delete security group")?;
+    }
+
+    if let Some(key_name) = key_name {
+        let mut req = DeleteKeyPairRequest::default();
+        req.key_name = Some(key_name.to_string());
+        ec2.delete_key_pair(req)
+            .await
+            .context("Failed to delete key pair")?;
+    }
+
+    Ok(())
+}
+
+/// Poll until every instance in `instance_ids` has reached the `terminated`
+/// state.
+async fn wait_for_termination(ec2: &Ec2Client, instance_ids: &[String]) -> Result<()> {
+    let mut backoff = Backoff::default();
+    let start = Instant::now();
+
+    loop {
+        if start.elapsed() > TERMINATION_TIMEOUT {
+            anyhow::bail!("timed out waiting for instances to reach the terminated state");
+        }
+
+        let mut req = DescribeInstancesRequest::default();
+        req.instance_ids = Some(instance_ids.to_vec());
+
+        let res = ec2
+            .describe_instances(req)
+            .await
+            .context("Failed to describe instances while waiting for termination")?;
+
+        let all_terminated = res
+            .reservations
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|reservation| reservation.instances.unwrap_or_default())
+            .all(|instance| {
+                instance
+                    .state
+                    .and_then(|state| state.name)
+                    .as_deref()
+                    == Some("terminated")
+            });
+
+        if all_terminated {
+            return Ok(());
+        }
+
+        backoff.wait().await;
+    }
+}
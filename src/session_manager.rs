@@ -0,0 +1,135 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::ssh::{CommandOutput, Session};
+use crate::Machine;
+
+/// A keyed registry of machine addresses that transparently reconnects a
+/// `Machine`'s SSH session whenever a command fails with a connection-level
+/// error. Idle SSH connections tend to die silently over the course of a
+/// multi-hour experiment; running commands through a `SessionManager`
+/// instead of calling `machine.ssh` directly keeps them usable for as long
+/// as the experiment runs.
+///
+/// Unlike a pool of owned sessions, `SessionManager` always leaves the
+/// reconnected session in `machine.ssh` - so `Machine::upload`/`download`
+/// and any other direct use of `machine.ssh` keep working for a managed
+/// machine, reconnects included.
+pub struct SessionManager {
+    addrs: HashMap<String, String>,
+    key_path: PathBuf,
+    connect_deadline: Duration,
+    max_reconnect_attempts: u32,
+    keepalive: Option<(bool, u32)>,
+}
+
+impl SessionManager {
+    /// `key_path` is the private key used to re-authenticate when a session
+    /// needs to be reconnected.
+    pub fn new(key_path: impl Into<PathBuf>) -> Self {
+        SessionManager {
+            addrs: HashMap::new(),
+            key_path: key_path.into(),
+            connect_deadline: Duration::from_secs(60),
+            max_reconnect_attempts: 3,
+            keepalive: None,
+        }
+    }
+
+    /// How long a reconnect attempt waits for the SSH port to open before
+    /// giving up. Defaults to 60 seconds.
+    pub fn connect_deadline(&mut self, deadline: Duration) {
+        self.connect_deadline = deadline;
+    }
+
+    /// How many times to reconnect and retry a command after a
+    /// connection-level failure before giving up and returning the error.
+    /// Defaults to 3.
+    pub fn max_reconnect_attempts(&mut self, attempts: u32) {
+        self.max_reconnect_attempts = attempts;
+    }
+
+    /// Enable SSH keepalive on managed sessions (`ssh2::Session::set_keepalive`).
+    /// Applied to every session registered or reconnected afterwards.
+    pub fn keepalive(&mut self, want_reply: bool, interval: u32) {
+        self.keepalive = Some((want_reply, interval));
+    }
+
+    /// Register `machine` under `key`, so a dropped connection can be
+    /// transparently re-established against `machine.ssh_addr()`.
+    /// `machine.ssh` must already hold an established session.
+    pub fn register(&mut self, key: &str, machine: &mut Machine) -> Result<()> {
+        let addr = machine.ssh_addr();
+
+        if let Some((want_reply, interval)) = self.keepalive {
+            machine
+                .ssh
+                .as_mut()
+                .with_context(|| format!("machine '{}' has no ssh session to register", key))?
+                .set_keepalive(want_reply, interval);
+        }
+
+        self.addrs.insert(key.to_string(), addr);
+        Ok(())
+    }
+
+    /// Run `cmd` on the session registered under `key`, reconnecting
+    /// `machine.ssh` and retrying if it fails with a connection-level error.
+    pub fn cmd(&mut self, key: &str, machine: &mut Machine, cmd: &str) -> Result<String> {
+        self.with_reconnect(key, machine, |session| session.cmd(cmd))
+    }
+
+    /// Like `cmd`, but returns the richer `CommandOutput` (stdout, stderr,
+    /// exit status).
+    pub fn cmd_status(
+        &mut self,
+        key: &str,
+        machine: &mut Machine,
+        cmd: &str,
+    ) -> Result<CommandOutput> {
+        self.with_reconnect(key, machine, |session| session.cmd_status(cmd))
+    }
+
+    fn with_reconnect<F, R>(&mut self, key: &str, machine: &mut Machine, f: F) -> Result<R>
+    where
+        F: Fn(&mut Session) -> Result<R>,
+    {
+        let addr = self
+            .addrs
+            .get(key)
+            .with_context(|| format!("no session registered for '{}'", key))?
+            .clone();
+
+        let mut attempt = 0;
+        loop {
+            let session = machine
+                .ssh
+                .as_mut()
+                .with_context(|| format!("machine '{}' has no ssh session", key))?;
+
+            match f(session) {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_reconnect_attempts && is_connection_error(&e) => {
+                    attempt += 1;
+                    let mut session =
+                        Session::connect(&addr, &self.key_path, self.connect_deadline)
+                            .with_context(|| format!("failed to reconnect to '{}' ({})", key, addr))?;
+                    if let Some((want_reply, interval)) = self.keepalive {
+                        session.set_keepalive(want_reply, interval);
+                    }
+                    machine.ssh = Some(session);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn is_connection_error(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|io_err| matches!(io_err.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe))
+}
@@ -1,38 +1,177 @@
-use anyhow::{Context, Error, Result};
-use ssh2;
-use std::io::ErrorKind;
+use anyhow::{bail, Context, Error, Result};
+use ssh2::{self, OpenFlags, OpenType};
+use std::fs::{self, File as LocalFile};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
 use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::thread;
 use std::time::{Duration, Instant};
 
 pub struct Session {
     ssh: ssh2::Session,
-    // stream: TcpStream,
+}
+
+/// The result of a completed remote command: its captured output on each
+/// stream plus the exit code the remote shell reported.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_status: i32,
+}
+
+/// Which stream an incremental chunk from `Command::stream` came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A command running on a remote machine. Write to it (via the `Write`
+/// impl) to feed its stdin, then call `wait` or `stream` to collect its
+/// output and exit status.
+pub struct Command {
+    session: ssh2::Session,
+    channel: ssh2::Channel,
+}
+
+impl Command {
+    /// Collect the command's full stdout/stderr and exit status, blocking
+    /// until it completes.
+    pub fn wait(mut self) -> Result<CommandOutput> {
+        self.channel
+            .send_eof()
+            .context("Failed to close command stdin")?;
+
+        let mut stdout = String::new();
+        self.channel
+            .read_to_string(&mut stdout)
+            .context("Error reading stdout")?;
+
+        let mut stderr = String::new();
+        self.channel
+            .stderr()
+            .read_to_string(&mut stderr)
+            .context("Error reading stderr")?;
+
+        self.finish(stdout, stderr)
+    }
+
+    /// Like `wait`, but invokes `on_chunk` with each incremental chunk of
+    /// stdout/stderr as it arrives, so long-running commands produce live
+    /// output instead of blocking until completion.
+    pub fn stream<F>(mut self, mut on_chunk: F) -> Result<CommandOutput>
+    where
+        F: FnMut(Stream, &[u8]),
+    {
+        self.channel
+            .send_eof()
+            .context("Failed to close command stdin")?;
+
+        self.session.set_blocking(false);
+        let result = self.read_until_eof(&mut on_chunk);
+        // `self.session` is a clone of the `Machine`'s shared session, so
+        // blocking mode must be restored on every exit path - including
+        // errors - or later `cmd`/`command` calls on the same machine start
+        // seeing spurious `WouldBlock`/short reads.
+        self.session.set_blocking(true);
+        let (stdout, stderr) = result?;
+
+        self.finish(
+            String::from_utf8_lossy(&stdout).into_owned(),
+            String::from_utf8_lossy(&stderr).into_owned(),
+        )
+    }
+
+    fn read_until_eof<F>(&mut self, on_chunk: &mut F) -> Result<(Vec<u8>, Vec<u8>)>
+    where
+        F: FnMut(Stream, &[u8]),
+    {
+        let mut stdout = Vec::new();
+        let mut stderr = Vec::new();
+        let mut buf = [0u8; 8192];
+
+        loop {
+            let mut made_progress = false;
+
+            match self.channel.read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    on_chunk(Stream::Stdout, &buf[..n]);
+                    stdout.extend_from_slice(&buf[..n]);
+                    made_progress = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Error reading stdout"),
+            }
+
+            match self.channel.stderr().read(&mut buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    on_chunk(Stream::Stderr, &buf[..n]);
+                    stderr.extend_from_slice(&buf[..n]);
+                    made_progress = true;
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e).context("Error reading stderr"),
+            }
+
+            if self.channel.eof() {
+                return Ok((stdout, stderr));
+            }
+
+            if !made_progress {
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    fn finish(mut self, stdout: String, stderr: String) -> Result<CommandOutput> {
+        self.channel.wait_close().context("Error closing channel")?;
+        let exit_status = self
+            .channel
+            .exit_status()
+            .context("Error reading exit status")?;
+
+        Ok(CommandOutput {
+            stdout,
+            stderr,
+            exit_status,
+        })
+    }
+}
+
+impl Write for Command {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.channel.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.channel.flush()
+    }
 }
 
 impl Session {
-    pub fn connect<A: ToSocketAddrs>(addr: A, key: &Path) -> Result<Self, Error> {
-        // let private_key_path = Path::new("flotilla-key-pair.pem");
-        // let public_key_path = Path::new("flotilla-key-pair.pub");
+    /// Block the current thread until an SSH session to `addr` is
+    /// established, or `deadline` elapses. Intended for non-async callers
+    /// such as the rayon worker threads `FlotillaBuilder::run` sets up
+    /// machines on.
+    pub fn connect<A: ToSocketAddrs>(addr: A, key: &Path, deadline: Duration) -> Result<Self, Error> {
         let start = Instant::now();
-        let timeout = Duration::from_secs(120);
 
         let tcp = loop {
-            // 1. Try to connect
             match TcpStream::connect(&addr) {
                 Ok(stream) => {
                     println!("SSH port is open!");
                     break stream;
                 }
                 Err(e) => {
-                    // 2. If timeout reached, crash with the error
-                    if start.elapsed() > timeout {
-                        // panic!("Timed out waiting for SSH on {}: {}", addr, e);
+                    if start.elapsed() > deadline {
+                        bail!("Timed out waiting for SSH port to open: {}", e);
                     }
 
-                    // 3. If Connection Refused, wait and retry
                     // We also handle "Resource temporarily unavailable" which can happen on bad networks
                     match e.kind() {
                         ErrorKind::ConnectionRefused | ErrorKind::TimedOut => {
@@ -46,6 +185,10 @@ impl Session {
             }
         };
 
+        Self::handshake(tcp, key)
+    }
+
+    fn handshake(tcp: TcpStream, key: &Path) -> Result<Self, Error> {
         let mut sess = ssh2::Session::new().context("Creating ssh session failed")?;
         sess.set_tcp_stream(tcp);
         sess.handshake()
@@ -57,15 +200,19 @@ impl Session {
         )
         .context("Failed to authenticate ssh session")?;
 
-        Ok(Session {
-            ssh: sess,
-            // stream: null,
-        })
+        Ok(Session { ssh: sess })
     }
 
-    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
-        use std::io::Read;
+    /// Enable SSH-level keepalive packets so idle connections survive long
+    /// gaps between commands instead of being silently dropped by a NAT or
+    /// firewall. `interval` is the number of seconds between keepalives;
+    /// `want_reply` requests a reply to each one, which also lets `cmd` and
+    /// friends detect a dead connection sooner.
+    pub fn set_keepalive(&mut self, want_reply: bool, interval: u32) {
+        self.ssh.set_keepalive(want_reply, interval);
+    }
 
+    pub fn cmd(&mut self, cmd: &str) -> Result<String> {
         let mut channel = self.ssh.channel_session().context(format!(
             "Failed to create session based channel for cmd '{}'",
             cmd
@@ -84,6 +231,97 @@ impl Session {
         // println!("{}", channel.exit_status()?);
         Ok(s)
     }
+
+    /// Start `cmd` on the remote machine. The returned `Command` can be
+    /// written to (to feed stdin) before collecting its output with `wait`
+    /// or `stream`.
+    pub fn command(&mut self, cmd: &str) -> Result<Command> {
+        let mut channel = self.ssh.channel_session().context(format!(
+            "Failed to create session based channel for cmd '{}'",
+            cmd
+        ))?;
+
+        channel
+            .exec(cmd)
+            .context(format!("Failed to execute the given command '{}'", cmd))?;
+
+        Ok(Command {
+            session: self.ssh.clone(),
+            channel,
+        })
+    }
+
+    /// Run `cmd` to completion and return its stdout, stderr and exit
+    /// status, so callers can detect a failed command instead of silently
+    /// continuing on nonzero exit.
+    pub fn cmd_status(&mut self, cmd: &str) -> Result<CommandOutput> {
+        self.command(cmd)?.wait()
+    }
+
+    /// Like `cmd_status`, but invokes `on_chunk` with incremental
+    /// stdout/stderr chunks as they arrive instead of blocking until the
+    /// command finishes.
+    pub fn cmd_stream<F>(&mut self, cmd: &str, on_chunk: F) -> Result<CommandOutput>
+    where
+        F: FnMut(Stream, &[u8]),
+    {
+        self.command(cmd)?.stream(on_chunk)
+    }
+
+    /// Upload `local` to `remote` over SFTP, preserving `local`'s permission
+    /// bits on the remote file.
+    pub fn upload(&mut self, local: &Path, remote: &Path) -> Result<()> {
+        let mut local_file = LocalFile::open(local)
+            .with_context(|| format!("Failed to open local file '{}'", local.display()))?;
+        let mode = local_file
+            .metadata()
+            .with_context(|| format!("Failed to stat local file '{}'", local.display()))?
+            .permissions()
+            .mode() as i32;
+
+        let sftp = self.ssh.sftp().context("Failed to start SFTP session")?;
+        let mut remote_file = sftp
+            .open_mode(
+                remote,
+                OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::CREATE,
+                mode,
+                OpenType::File,
+            )
+            .with_context(|| format!("Failed to open remote file '{}' for writing", remote.display()))?;
+
+        io::copy(&mut local_file, &mut remote_file).with_context(|| {
+            format!("Failed to upload '{}' to '{}'", local.display(), remote.display())
+        })?;
+
+        Ok(())
+    }
+
+    /// Download `remote` to `local` over SFTP, preserving `remote`'s
+    /// permission bits on the local file.
+    pub fn download(&mut self, remote: &Path, local: &Path) -> Result<()> {
+        let sftp = self.ssh.sftp().context("Failed to start SFTP session")?;
+        let mut remote_file = sftp
+            .open(remote)
+            .with_context(|| format!("Failed to open remote file '{}' for reading", remote.display()))?;
+        let mode = remote_file
+            .stat()
+            .with_context(|| format!("Failed to stat remote file '{}'", remote.display()))?
+            .perm
+            .unwrap_or(0o644);
+
+        let mut local_file = LocalFile::create(local)
+            .with_context(|| format!("Failed to create local file '{}'", local.display()))?;
+
+        io::copy(&mut remote_file, &mut local_file).with_context(|| {
+            format!("Failed to download '{}' to '{}'", remote.display(), local.display())
+        })?;
+
+        local_file
+            .set_permissions(fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions on '{}'", local.display()))?;
+
+        Ok(())
+    }
 }
 
 impl Deref for Session {